@@ -0,0 +1,77 @@
+use std::io::{self, BufRead, Write};
+
+use anyhow::Result;
+use serde_json::{json, to_value, Value};
+
+use crate::{
+    dns::DnsConfig,
+    geo::{self, GeoReaders},
+};
+
+/// Read one IP (or hostname) per line from stdin and write one compact
+/// JSON value per line (NDJSON) to stdout, reusing the `readers` passed in
+/// rather than reopening them per line.
+///
+/// A line that fails to resolve emits an error object rather than aborting
+/// the whole run. A hostname resolving to several addresses is reported as
+/// a JSON array, same as the CLI's multi-address branch, rather than
+/// expanding into several output lines, so the stream stays 1:1 with the
+/// non-blank input lines. Blank lines are skipped and produce no output.
+pub fn run(
+    readers: &GeoReaders,
+    lang_codes: &[String],
+    last_subdiv: bool,
+    dns_config: &DnsConfig,
+) -> Result<()> {
+    let stdin = io::stdin();
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+
+    for line in stdin.lock().lines() {
+        let line = line?;
+        let target = line.trim();
+        if target.is_empty() {
+            continue;
+        }
+
+        let record = process_line(readers, target, lang_codes, last_subdiv, dns_config)
+            .unwrap_or_else(|err| json!({ "input": target, "error": err.to_string() }));
+
+        writeln!(out, "{}", serde_json::to_string(&record)?)?;
+    }
+
+    Ok(())
+}
+
+fn process_line(
+    readers: &GeoReaders,
+    target: &str,
+    lang_codes: &[String],
+    last_subdiv: bool,
+    dns_config: &DnsConfig,
+) -> Result<Value> {
+    let resolved = dns_config.resolve_target(target)?;
+
+    let records: Vec<Value> = resolved
+        .into_iter()
+        .map(
+            |(ipaddr, queried_name)| match geo::lookup_ip_info(
+                readers,
+                ipaddr,
+                queried_name,
+                lang_codes,
+                last_subdiv,
+                dns_config,
+            )? {
+                Ok(info) => Ok(to_value(info)?),
+                Err(bogon) => Ok(to_value(bogon)?),
+            },
+        )
+        .collect::<Result<_>>()?;
+
+    if records.len() == 1 {
+        Ok(records.into_iter().next().unwrap())
+    } else {
+        Ok(Value::Array(records))
+    }
+}
@@ -0,0 +1,132 @@
+use std::{net::IpAddr, path::Path};
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+
+/// DNS resolution behaviour, modeled on echoip-slatecave's `DnsConfig`:
+/// whether to perform the reverse PTR lookup at all, whether to suppress
+/// hostnames for non-global addresses, which hostname suffixes to hide
+/// (e.g. `.1e100.net`, or an operator's internal corp domains), and
+/// whether a hostname given on the command line may be forward-resolved.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct DnsConfig {
+    pub allow_reverse_lookup: bool,
+    pub allow_forward_lookup: bool,
+    pub hide_private_range_ips: bool,
+    pub hidden_suffixes: Vec<String>,
+}
+
+impl Default for DnsConfig {
+    fn default() -> Self {
+        Self {
+            allow_reverse_lookup: true,
+            allow_forward_lookup: true,
+            hide_private_range_ips: false,
+            hidden_suffixes: Vec::new(),
+        }
+    }
+}
+
+impl DnsConfig {
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read DNS config {}", path.display()))?;
+        toml::from_str(&text)
+            .with_context(|| format!("Failed to parse DNS config {}", path.display()))
+    }
+
+    /// Resolve the PTR hostname for `ipaddr`, or `None` if reverse lookups
+    /// are disabled, the address is private and configured to be hidden,
+    /// the lookup fails, or the result matches a hidden suffix.
+    pub fn resolve_hostname(&self, ipaddr: &IpAddr) -> Option<String> {
+        if !self.allow_reverse_lookup {
+            return None;
+        }
+
+        if self.hide_private_range_ips && !ipaddr.is_global() {
+            return None;
+        }
+
+        let hostname = dns_lookup::lookup_addr(ipaddr).ok()?;
+
+        if self
+            .hidden_suffixes
+            .iter()
+            .any(|suffix| hostname_matches_suffix(&hostname, suffix))
+        {
+            return None;
+        }
+
+        Some(hostname)
+    }
+
+    /// Resolve a CLI `target`, either an IP address or a hostname, into the
+    /// address(es) to run the geo lookup on. An address resolves to itself;
+    /// a hostname is forward-resolved via DNS (A/AAAA), gated by
+    /// `allow_forward_lookup`, and each resulting address is paired with
+    /// the original name so the output stays traceable.
+    pub fn resolve_target(&self, target: &str) -> Result<Vec<(IpAddr, Option<String>)>> {
+        if let Ok(addr) = target.parse::<IpAddr>() {
+            return Ok(vec![(addr, None)]);
+        }
+
+        if !self.allow_forward_lookup {
+            bail!("Forward DNS lookup is disabled, and \"{target}\" is not an IP address");
+        }
+
+        let addrs = dns_lookup::lookup_host(target)
+            .with_context(|| format!("Failed to resolve hostname {target}"))?;
+
+        Ok(addrs
+            .into_iter()
+            .map(|addr| (addr, Some(target.to_string())))
+            .collect())
+    }
+}
+
+/// Whether `hostname` is `suffix` itself or a subdomain of it, anchored on
+/// the label boundary so a configured suffix like `corp.internal` (missing
+/// the leading dot from the request's own example, `.1e100.net`) doesn't
+/// also suppress unrelated hosts like `fake-corp.internal` that merely
+/// share the trailing characters.
+fn hostname_matches_suffix(hostname: &str, suffix: &str) -> bool {
+    let suffix = suffix.trim_start_matches('.');
+    hostname == suffix || hostname.ends_with(&format!(".{suffix}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::hostname_matches_suffix;
+
+    #[test]
+    fn matches_a_subdomain_of_a_dotted_suffix() {
+        assert!(hostname_matches_suffix(
+            "zrh04s16-in-f14.1e100.net",
+            ".1e100.net"
+        ));
+    }
+
+    #[test]
+    fn matches_a_subdomain_of_a_bare_suffix() {
+        assert!(hostname_matches_suffix("host.corp.internal", "corp.internal"));
+    }
+
+    #[test]
+    fn matches_the_suffix_itself() {
+        assert!(hostname_matches_suffix("corp.internal", "corp.internal"));
+    }
+
+    #[test]
+    fn does_not_match_a_hostname_that_merely_shares_trailing_characters() {
+        assert!(!hostname_matches_suffix(
+            "fake-corp.internal",
+            "corp.internal"
+        ));
+    }
+
+    #[test]
+    fn does_not_match_an_unrelated_hostname() {
+        assert!(!hostname_matches_suffix("example.com", "corp.internal"));
+    }
+}
@@ -0,0 +1,379 @@
+use std::{default::Default, fs, path::Path};
+
+use anyhow::{anyhow, Context, Result};
+use geoip2::{City, Reader, ASN};
+use serde::Serialize;
+
+const ASN_IP2: &str = "GeoIP2-ASN.mmdb";
+const ASN_LITE2: &str = "GeoLite2-ASN.mmdb";
+const CITY_IP2: &str = "GeoIP2-City.mmdb";
+const CITY_LITE2: &str = "GeoLite2-City.mmdb";
+
+#[derive(Serialize)]
+pub struct IpInfo {
+    pub ip: String, // "142.250.203.110"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub queried_name: Option<String>, // "google.com", when the input was a hostname
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hostname: Option<String>, // "zrh04s16-in-f14.1e100.net"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub continent: Option<String>, // "Europe"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub continent_iso: Option<String>, // "EU"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub city: Option<String>, // "ZÃ¼rich"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub city_geoname_id: Option<u32>, // 2657896
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub region_iso: Option<String>, // "ZH"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub region_geoname_id: Option<u32>, // 2657895
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub country_iso: Option<String>, // "CH"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub country_geoname_id: Option<u32>, // 2658434
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub registered_country_iso: Option<String>, // "CH"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub represented_country_iso: Option<String>, // "CH"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub long: Option<f64>, // 47.3667
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lat: Option<f64>, // 8.5500
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub accuracy_radius: Option<u16>, // 50
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub osm: Option<String>, // "https://openstreetmap.org/#map=11/47.3667/8.5500"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub org: Option<String>, // "AS15169 Google LLC"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub postal: Option<String>, // "8000"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timezone: Option<String>, // "Europe/Zurich"
+}
+
+// Not routed
+#[derive(Serialize)]
+pub struct IpBogon {
+    pub ip: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub queried_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hostname: Option<String>,
+    pub bogon: bool,
+}
+
+impl Default for IpBogon {
+    fn default() -> Self {
+        Self {
+            ip: String::from(""),
+            queried_name: None,
+            hostname: None,
+            bogon: true,
+        }
+    }
+}
+
+/// The City and ASN `Reader`s, opened once and shared by every lookup for
+/// the lifetime of the process (the CLI does one lookup and exits, the
+/// `--serve` mode keeps these around behind an `Arc` for as long as it runs).
+pub struct GeoReaders {
+    pub city: Reader<'static, City<'static>>,
+    pub asn: Reader<'static, ASN<'static>>,
+}
+
+impl GeoReaders {
+    pub fn open(dir: &Path) -> Result<Self> {
+        let dir = dir.to_string_lossy();
+
+        let city_bytes = read_mmdb(&dir, CITY_IP2, CITY_LITE2)
+            .with_context(|| format!("Failed to read City mmdb in {dir}"))?;
+        let city = Reader::<City>::from_bytes(Box::leak(city_bytes.into_boxed_slice()))
+            .ok()
+            .context("Failed to create City reader")?;
+
+        let asn_bytes = read_mmdb(&dir, ASN_IP2, ASN_LITE2)
+            .with_context(|| format!("Failed to read ASN mmdb in {dir}"))?;
+        let asn = Reader::<ASN>::from_bytes(Box::leak(asn_bytes.into_boxed_slice()))
+            .ok()
+            .context("Failed to create ASN reader")?;
+
+        Ok(Self { city, asn })
+    }
+}
+
+fn read_mmdb(dir: &str, preferred: &str, fallback: &str) -> Result<Vec<u8>> {
+    match fs::read(format!("{dir}/{preferred}")) {
+        Ok(bf) => Ok(bf),
+        Err(_) => Ok(fs::read(format!("{dir}/{fallback}"))?),
+    }
+}
+
+/// Walk `lang_codes` in priority order and return the first name present in
+/// `names`. Falls back to `en`, then to whatever entry happens to be left,
+/// rather than panicking on sparse GeoLite2 data.
+///
+/// `names` comes in as an iterator rather than a `geoip2::decoder::Map`
+/// directly, since that type is private to the `geoip2` crate.
+fn pick_name<'a>(
+    names: impl Iterator<Item = (&'a str, &'a str)> + Clone,
+    lang_codes: &[String],
+) -> Option<&'a str> {
+    lang_codes
+        .iter()
+        .find_map(|lang| {
+            names
+                .clone()
+                .find(|(k, _)| *k == lang.as_str())
+                .map(|(_, v)| v)
+        })
+        .or_else(|| names.clone().find(|(k, _)| *k == "en").map(|(_, v)| v))
+        .or_else(|| names.clone().next().map(|(_, v)| v))
+}
+
+pub fn get_some_city(geo: &City, lang_codes: &[String]) -> Option<String> {
+    if let Some(city) = geo.city.as_ref() {
+        city.names
+            .as_ref()
+            .and_then(|names| pick_name(names.iter().copied(), lang_codes))
+            .map(String::from)
+    } else {
+        None
+    }
+}
+
+pub fn get_some_continent(geo: &City, lang_codes: &[String]) -> Option<String> {
+    geo.continent
+        .as_ref()
+        .and_then(|continent| continent.names.as_ref())
+        .and_then(|names| pick_name(names.iter().copied(), lang_codes))
+        .map(String::from)
+}
+
+pub fn get_some_continent_iso(geo: &City) -> Option<String> {
+    if let Some(continent) = geo.continent.as_ref() {
+        continent.code.map(String::from)
+    } else {
+        None
+    }
+}
+
+pub fn get_some_city_geoname_id(geo: &City) -> Option<u32> {
+    geo.city.as_ref().and_then(|city| city.geoname_id)
+}
+
+pub fn get_some_region_geoname_id(geo: &City, last: bool) -> Option<u32> {
+    let subdivs = geo.subdivisions.as_ref();
+
+    subdivs.and_then(|subdiv| {
+        let subdiv = if last { subdiv.last() } else { subdiv.first() };
+        subdiv.and_then(|subdiv| subdiv.geoname_id)
+    })
+}
+
+pub fn get_some_country_geoname_id(geo: &City) -> Option<u32> {
+    geo.country.as_ref().and_then(|country| country.geoname_id)
+}
+
+pub fn get_some_registered_country_iso(geo: &City) -> Option<String> {
+    if let Some(country) = geo.registered_country.as_ref() {
+        country.iso_code.map(String::from)
+    } else {
+        None
+    }
+}
+
+pub fn get_some_represented_country_iso(geo: &City) -> Option<String> {
+    if let Some(country) = geo.represented_country.as_ref() {
+        country.iso_code.map(String::from)
+    } else {
+        None
+    }
+}
+
+pub fn get_some_accuracy_radius(geo: &City) -> Option<u16> {
+    if let Some(location) = geo.location.as_ref() {
+        location.accuracy_radius
+    } else {
+        None
+    }
+}
+
+pub fn get_some_region_iso(geo: &City, last: bool) -> Option<String> {
+    let subdivs = geo.subdivisions.as_ref();
+
+    subdivs.and_then(|subdiv| {
+        let subdiv = if last { subdiv.last() } else { subdiv.first() };
+        match subdiv {
+            Some(subdiv) => subdiv.iso_code.map(|code| code.to_string()),
+            _ => None,
+        }
+    })
+}
+
+pub fn get_some_country_iso(geo: &City) -> Option<String> {
+    if let Some(country) = geo.country.as_ref() {
+        country.iso_code.map(String::from)
+    } else {
+        None
+    }
+}
+
+pub fn get_some_loc(geo: &City) -> Option<(f64, f64)> {
+    if let Some(loc) = geo.location.as_ref() {
+        match (loc.latitude, loc.longitude) {
+            (Some(lat), Some(long)) => Some((lat, long)),
+            _ => None,
+        }
+    } else {
+        None
+    }
+}
+
+pub fn get_some_org(asn: ASN) -> Option<String> {
+    match (
+        asn.autonomous_system_number,
+        asn.autonomous_system_organization,
+    ) {
+        (Some(number), Some(organization)) => Some(format!("AS{number} {organization}")),
+        (Some(number), None) => Some(format!("AS{number}")),
+        (None, Some(organization)) => Some(organization.to_string()),
+        (None, None) => None,
+    }
+}
+
+pub fn get_some_postal(geo: &City) -> Option<String> {
+    if let Some(postal) = geo.postal.as_ref() {
+        postal.code.map(String::from)
+    } else {
+        None
+    }
+}
+
+pub fn get_some_tz(geo: &City) -> Option<String> {
+    if let Some(location) = geo.location.as_ref() {
+        location.time_zone.map(String::from)
+    } else {
+        None
+    }
+}
+
+/// Run the full City + ASN lookup for a single address and assemble the
+/// `IpInfo` record, or an `IpBogon` record for non-global addresses.
+///
+/// Shared by the one-shot CLI path and every `--serve` request handler so
+/// both produce identical output from the same already-open readers.
+pub fn lookup_ip_info(
+    readers: &GeoReaders,
+    ipaddr: std::net::IpAddr,
+    queried_name: Option<String>,
+    lang_codes: &[String],
+    last_subdiv: bool,
+    dns_config: &crate::dns::DnsConfig,
+) -> Result<Result<IpInfo, IpBogon>> {
+    /*
+     * If not globally routable, we make it real quick.
+     *
+     * (Using `.is_global()` depends on a nightly feature in IpAddr which has to be imported as `feature(ip)`.)
+     */
+    if !ipaddr.is_global() {
+        return Ok(Err(IpBogon {
+            ip: ipaddr.to_string(),
+            queried_name,
+            hostname: dns_config.resolve_hostname(&ipaddr),
+            ..Default::default()
+        }));
+    }
+
+    let geo = match readers.city.lookup(ipaddr) {
+        Ok(geo) => Some(geo),
+        Err(geoip2::Error::NotFound) => None,
+        Err(err) => return Err(anyhow!("Failed to query City mmdb: {err:?}")),
+    };
+    let asn = match readers.asn.lookup(ipaddr) {
+        Ok(asn) => Some(asn),
+        Err(geoip2::Error::NotFound) => None,
+        Err(err) => return Err(anyhow!("Failed to query ASN mmdb: {err:?}")),
+    };
+
+    let long;
+    let lat;
+    let osm;
+
+    if let Some(location) = geo.as_ref().and_then(get_some_loc) {
+        let (longitude, latitude) = location;
+        long = Some(longitude);
+        lat = Some(latitude);
+        osm = Some(format!(
+            "https://openstreetmap.org/#map=11/{longitude}/{latitude}"
+        ));
+    } else {
+        long = None;
+        lat = None;
+        osm = None;
+    }
+
+    Ok(Ok(IpInfo {
+        ip: ipaddr.to_string(),
+        queried_name,
+        hostname: dns_config.resolve_hostname(&ipaddr),
+        continent: geo.as_ref().and_then(|geo| get_some_continent(geo, lang_codes)),
+        continent_iso: geo.as_ref().and_then(get_some_continent_iso),
+        city: geo.as_ref().and_then(|geo| get_some_city(geo, lang_codes)),
+        city_geoname_id: geo.as_ref().and_then(get_some_city_geoname_id),
+        region_iso: geo.as_ref().and_then(|geo| get_some_region_iso(geo, last_subdiv)),
+        region_geoname_id: geo
+            .as_ref()
+            .and_then(|geo| get_some_region_geoname_id(geo, last_subdiv)),
+        country_iso: geo.as_ref().and_then(get_some_country_iso),
+        country_geoname_id: geo.as_ref().and_then(get_some_country_geoname_id),
+        registered_country_iso: geo.as_ref().and_then(get_some_registered_country_iso),
+        represented_country_iso: geo.as_ref().and_then(get_some_represented_country_iso),
+        long,
+        lat,
+        accuracy_radius: geo.as_ref().and_then(get_some_accuracy_radius),
+        osm,
+        org: asn.and_then(get_some_org),
+        postal: geo.as_ref().and_then(get_some_postal),
+        timezone: geo.as_ref().and_then(get_some_tz),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::pick_name;
+
+    const NAMES: &[(&str, &str)] = &[("de", "Zürich"), ("en", "Zurich"), ("ja", "チューリッヒ")];
+
+    fn pick<'a>(names: &[(&'a str, &'a str)], lang_codes: &[&str]) -> Option<&'a str> {
+        let lang_codes: Vec<String> = lang_codes.iter().map(|s| s.to_string()).collect();
+        pick_name(names.iter().copied(), &lang_codes)
+    }
+
+    #[test]
+    fn prefers_the_first_priority_language_present() {
+        assert_eq!(pick(NAMES, &["fr", "ja", "de"]), Some("チューリッヒ"));
+    }
+
+    #[test]
+    fn falls_back_to_en_when_no_priority_language_matches() {
+        assert_eq!(pick(NAMES, &["fr"]), Some("Zurich"));
+    }
+
+    #[test]
+    fn falls_back_to_any_name_when_en_is_also_absent() {
+        let names: &[(&str, &str)] = &[("ja", "チューリッヒ")];
+        assert_eq!(pick(names, &["fr"]), Some("チューリッヒ"));
+    }
+
+    #[test]
+    fn returns_none_for_an_empty_names_map() {
+        assert_eq!(pick(&[], &["en"]), None);
+    }
+
+    #[test]
+    fn a_duplicate_code_resolves_to_its_first_occurrence() {
+        let names: &[(&str, &str)] = &[("en", "first"), ("en", "second")];
+        assert_eq!(pick(names, &["en"]), Some("first"));
+    }
+}
@@ -1,58 +1,17 @@
 #![feature(ip)]
 
-use std::{default::Default, fs, net::IpAddr, path::PathBuf};
+mod batch;
+mod dns;
+mod geo;
+mod server;
+
+use std::{net::SocketAddr, path::PathBuf};
 
 use anyhow::{Context, Result};
+use axum_client_ip::SecureClientIpSource;
 use clap::{command, Arg, ArgAction, ArgGroup, Id};
-use dns_lookup::lookup_addr;
-use geoip2::{City, Reader, ASN};
-use serde::Serialize;
-
-const ASN_IP2: &str = "GeoIP2-ASN.mmdb";
-const ASN_LITE2: &str = "GeoLite2-ASN.mmdb";
-const CITY_IP2: &str = "GeoIP2-City.mmdb";
-const CITY_LITE2: &str = "GeoLite2-City.mmdb";
-
-#[derive(Serialize)]
-struct IpInfo {
-    ip: String, // "142.250.203.110"
-    #[serde(skip_serializing_if = "Option::is_none")]
-    hostname: Option<String>, // "zrh04s16-in-f14.1e100.net"
-    #[serde(skip_serializing_if = "Option::is_none")]
-    city: Option<String>, // "ZÃ¼rich"
-    #[serde(skip_serializing_if = "Option::is_none")]
-    region_iso: Option<String>, // "ZH"
-    #[serde(skip_serializing_if = "Option::is_none")]
-    country_iso: Option<String>, // "CH"
-    #[serde(skip_serializing_if = "Option::is_none")]
-    long: Option<f64>, // 47.3667
-    #[serde(skip_serializing_if = "Option::is_none")]
-    lat: Option<f64>, // 8.5500
-    #[serde(skip_serializing_if = "Option::is_none")]
-    osm: Option<String>, // "https://openstreetmap.org/#map=11/47.3667/8.5500"
-    #[serde(skip_serializing_if = "Option::is_none")]
-    org: Option<String>, // "AS15169 Google LLC"
-    #[serde(skip_serializing_if = "Option::is_none")]
-    postal: Option<String>, // "8000"
-    #[serde(skip_serializing_if = "Option::is_none")]
-    timezone: Option<String>, // "Europe/Zurich"
-}
-
-// Not routed
-#[derive(Serialize)]
-struct IpBogon {
-    ip: String,
-    bogon: bool,
-}
 
-impl Default for IpBogon {
-    fn default() -> Self {
-        Self {
-            ip: String::from(""),
-            bogon: true,
-        }
-    }
-}
+use geo::GeoReaders;
 
 fn main() -> Result<()> {
     // Define cmdl interface properties.
@@ -67,15 +26,16 @@ fn main() -> Result<()> {
         )
         .arg(
             Arg::new("ipaddr")
-                .required(true)
-                .help("[-m <mmdbdir>] [--lang <langcode>] [--last] Query geoip info")
-                .value_parser(clap::value_parser!(IpAddr)),
+                .required_unless_present_any(["serve", "list_languages", "batch"])
+                .help("[-m <mmdbdir>] [--lang <langcode>] [--last] Query geoip info for an IP address or hostname")
+                .value_name("ip-or-hostname"),
         )
         .arg(
             Arg::new("langcode")
                 .long("lang")
-                .help("IETF language code used to query names")
-                .default_value("en"),
+                .help("Comma-separated IETF language-code priority list used to query names, e.g. \"de,fr,en\" (falls back to \"en\", then any name present)")
+                .default_value("en")
+                .value_delimiter(','),
         )
         .arg(
             Arg::new("last_subdiv")
@@ -83,6 +43,60 @@ fn main() -> Result<()> {
                 .help("For region details read last subdivision rather than first")
                 .action(ArgAction::SetTrue),
         )
+        .next_help_heading("Service mode")
+        .arg(
+            Arg::new("serve")
+                .long("serve")
+                .help("[-m <mmdbdir>] [--lang <langcode>] [--last] [--ip-source <source>] Serve geoip info over HTTP instead of a one-shot lookup")
+                .value_name("addr:port")
+                .value_parser(clap::value_parser!(SocketAddr)),
+        )
+        .arg(
+            Arg::new("ip_source")
+                .long("ip-source")
+                .help("Where to read the calling client's IP from, behind a reverse proxy")
+                .value_parser(["rightmost-forwarded-for", "x-real-ip", "socket"])
+                .default_value("socket"),
+        )
+        .next_help_heading("Batch mode")
+        .arg(
+            Arg::new("batch")
+                .long("batch")
+                .help("[-m <mmdbdir>] [--lang <langcode>] [--last] Read one IP or hostname per line from stdin, write NDJSON to stdout")
+                .action(ArgAction::SetTrue)
+                .conflicts_with_all(["ipaddr", "serve", "list_languages"]),
+        )
+        .next_help_heading("DNS")
+        .arg(
+            Arg::new("dns_config")
+                .long("dns-config")
+                .help("Path to a TOML file configuring reverse- and forward-DNS behaviour")
+                .value_parser(clap::value_parser!(PathBuf)),
+        )
+        .arg(
+            Arg::new("no_reverse_lookup")
+                .long("no-reverse-lookup")
+                .help("Skip the reverse PTR lookup entirely")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("no_forward_lookup")
+                .long("no-forward-lookup")
+                .help("Refuse to forward-resolve a hostname given as the lookup target")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("hide_private_range_ips")
+                .long("hide-private-range-ips")
+                .help("Suppress hostnames resolved for non-global addresses")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("hidden_suffix")
+                .long("hidden-suffix")
+                .help("Hostname suffix to omit from output, e.g. \".1e100.net\" (repeatable)")
+                .action(ArgAction::Append),
+        )
         .next_help_heading("Metainfo only") // Structure help in a slightly clearer way.
         .arg(
             Arg::new("list_languages")
@@ -92,36 +106,61 @@ fn main() -> Result<()> {
         )
         .group(
             ArgGroup::new("lookup")
-                .args(["ipaddr", "langcode", "last_subdiv"])
+                .arg("ipaddr")
+                .conflicts_with_all(["listonly", "servemode"]),
+        )
+        .group(
+            ArgGroup::new("servemode")
+                .args(["serve", "ip_source"])
                 .multiple(true)
-                .conflicts_with("listonly"),
+                .conflicts_with_all(["lookup", "listonly"]),
         )
         .group(
             ArgGroup::new("listonly")
                 .arg("list_languages")
-                .conflicts_with("lookup"),
+                .conflicts_with_all(["lookup", "servemode"]),
         )
         .get_matches();
 
     // Get DB directory.
     let Some(dir) = matches.get_one::<PathBuf>("mmdbdir") else { panic!("required") };
-    let dir = dir.to_string_lossy();
+
+    let lang_codes: Vec<String> = matches
+        .get_many::<String>("langcode")
+        .map(|codes| codes.cloned().collect())
+        .unwrap_or_default();
+
+    let dns_config = build_dns_config(&matches)?;
+
+    if let Some(addr) = matches.get_one::<SocketAddr>("serve") {
+        let Some(last_subdiv) = matches.get_one::<bool>("last_subdiv") else { panic!("required") };
+        let Some(ip_source) = matches.get_one::<String>("ip_source") else { panic!("required") };
+
+        let ip_source = match ip_source.as_str() {
+            "rightmost-forwarded-for" => SecureClientIpSource::RightmostXForwardedFor,
+            "x-real-ip" => SecureClientIpSource::XRealIp,
+            _ => SecureClientIpSource::ConnectInfo,
+        };
+
+        let readers = GeoReaders::open(dir).context("Failed to open geoip databases")?;
+
+        return server::run(*addr, readers, lang_codes, *last_subdiv, dns_config, ip_source);
+    }
+
+    if matches.get_flag("batch") {
+        let Some(last_subdiv) = matches.get_one::<bool>("last_subdiv") else { panic!("required") };
+
+        let readers = GeoReaders::open(dir).context("Failed to open geoip databases")?;
+
+        return batch::run(&readers, &lang_codes, *last_subdiv, &dns_config);
+    }
 
     // Initialize City DB reader.
-    let buffer = match fs::read(format!("{}/{}", dir, CITY_IP2)) {
-        Ok(bf) => bf,
-        Err(_) => fs::read(format!("{}/{}", dir, CITY_LITE2))
-            .ok()
-            .with_context(|| format!("Failed to read City mmdb in {dir}"))?,
-    };
-    let rdr_city = Reader::<City>::from_bytes(&buffer)
-        .ok()
-        .context("Failed to create City reader")?;
-    //eprintln!("{:?}", rdr_city.get_metadata());
+    let readers = GeoReaders::open(dir).context("Failed to open geoip databases")?;
 
     if matches.get_one::<Id>("listonly").is_some() {
         // Implementation details for metadata
-        let languages = &rdr_city.get_metadata().languages;
+        let languages = &readers.city.get_metadata().languages;
         languages
             .iter()
             .enumerate()
@@ -135,86 +174,32 @@ fn main() -> Result<()> {
             .for_each(drop); // As we just want to print, we need no result.
     } else if matches.get_one::<Id>("lookup").is_some() {
         // Implementation details for lookup
-        let Some(ipaddr) = matches.get_one::<IpAddr>("ipaddr") else { panic!("required") };
-        let Some(lang_code) = matches.get_one::<String>("langcode") else { panic!("required") };
+        let Some(target) = matches.get_one::<String>("ipaddr") else { panic!("required") };
         let Some(last_subdiv) = matches.get_one::<bool>("last_subdiv") else { panic!("required") };
 
-        /*
-         * If not globally routable, we make it real quick.
-         *
-         * (Using `.is_global()` depends on a nightly feature in IpAddr which has to be imported as `feature(ip)`.)
-         */
-        if !ipaddr.is_global() {
-            println!(
-                "{}",
-                serde_json::to_string_pretty(&IpBogon {
-                    ip: ipaddr.to_string(),
-                    ..Default::default()
-                })?
-            );
-
-            return Ok(());
-        }
-
-        // Initialize ASN DB reader.
-        let buffer = match fs::read(format!("{}/{}", dir, ASN_IP2)) {
-            Ok(bf) => bf,
-            Err(_) => fs::read(format!("{}/{}", dir, ASN_LITE2))
-                .ok()
-                .with_context(|| format!("Failed to read ASN mmdb in {}", dir))?,
-        };
-        let rdr_asn = Reader::<ASN>::from_bytes(&buffer)
-            .ok()
-            .context("Failed to create ASN reader")?;
-        //eprintln!("{:?}", rdr_asn.get_metadata());
-
-        // Get geo entry for ip address on city level.
-        let geo = rdr_city
-            .lookup(*ipaddr)
-            .ok()
-            .context("Failed to query City mmdb")?;
-
-        // Get ASN entry for ip address.
-        let org = rdr_asn
-            .lookup(*ipaddr)
-            .ok()
-            .context("Failed to query ASN mmdb")?;
-
-        // Prepare location data.
-        let long;
-        let lat;
-        let osm;
-
-        if let Some(location) = get_some_loc(&geo) {
-            let (longitude, latitude) = location;
-            long = Some(longitude);
-            lat = Some(latitude);
-            osm = Some(format!(
-                "https://openstreetmap.org/#map=11/{longitude}/{latitude}"
-            ));
+        let resolved = dns_config
+            .resolve_target(target)
+            .with_context(|| format!("Failed to resolve {target}"))?;
+
+        // A bare IP address resolves to exactly one entry and keeps printing a
+        // single object, as before; a hostname resolving to several addresses
+        // is reported as a JSON array so no result is silently dropped.
+        if resolved.len() == 1 {
+            let (ipaddr, queried_name) = resolved.into_iter().next().unwrap();
+            match geo::lookup_ip_info(&readers, ipaddr, queried_name, &lang_codes, *last_subdiv, &dns_config)? {
+                Ok(info) => println!("{}", serde_json::to_string_pretty(&info)?),
+                Err(bogon) => println!("{}", serde_json::to_string_pretty(&bogon)?),
+            }
         } else {
-            long = None;
-            lat = None;
-            osm = None;
+            let mut records = Vec::with_capacity(resolved.len());
+            for (ipaddr, queried_name) in resolved {
+                match geo::lookup_ip_info(&readers, ipaddr, queried_name, &lang_codes, *last_subdiv, &dns_config)? {
+                    Ok(info) => records.push(serde_json::to_value(info)?),
+                    Err(bogon) => records.push(serde_json::to_value(bogon)?),
+                }
+            }
+            println!("{}", serde_json::to_string_pretty(&records)?);
         }
-
-        // Serialize as JSON and write.
-        println!(
-            "{}",
-            serde_json::to_string_pretty(&IpInfo {
-                ip: ipaddr.to_string(),
-                hostname: lookup_addr(&ipaddr).ok(),
-                city: get_some_city(&geo, lang_code),
-                region_iso: get_some_region_iso(&geo, *last_subdiv),
-                country_iso: get_some_country_iso(&geo),
-                long,
-                lat,
-                osm,
-                org: get_some_org(org),
-                postal: get_some_postal(&geo),
-                timezone: get_some_tz(&geo)
-            })?
-        );
     } else {
         panic!("fresh out of arg group matches") // Never happens, unless cmdl interface is
                                                  // changed.
@@ -223,76 +208,26 @@ fn main() -> Result<()> {
     Ok(())
 }
 
-fn get_some_city(geo: &City, lang_code: &str) -> Option<String> {
-    if let Some(city) = geo.city.as_ref() {
-        city.names
-            .as_ref()
-            // TODO:    better than unwrap() !
-            //          Maybe don't always take `en`.
-            .map(|map| {
-                //eprintln!("{:#?}", map.key());
-                map.get(lang_code).unwrap().to_string()
-            })
-    } else {
-        None
-    }
-}
-
-fn get_some_region_iso(geo: &City, last: bool) -> Option<String> {
-    let subdivs = geo.subdivisions.as_ref();
-
-    subdivs.and_then(|subdiv| {
-        let subdiv = if last { subdiv.last() } else { subdiv.first() };
-        match subdiv {
-            Some(subdiv) => subdiv.iso_code.map(|code| code.to_string()),
-            _ => None,
-        }
-    })
-}
+/// Build the effective `DnsConfig` from an optional TOML file, overridden
+/// by whichever CLI flags the user also passed.
+fn build_dns_config(matches: &clap::ArgMatches) -> Result<dns::DnsConfig> {
+    let mut dns_config = match matches.get_one::<PathBuf>("dns_config") {
+        Some(path) => dns::DnsConfig::from_file(path)?,
+        None => dns::DnsConfig::default(),
+    };
 
-fn get_some_country_iso(geo: &City) -> Option<String> {
-    if let Some(country) = geo.country.as_ref() {
-        country.iso_code.map(String::from)
-    } else {
-        None
+    if matches.get_flag("no_reverse_lookup") {
+        dns_config.allow_reverse_lookup = false;
     }
-}
-
-fn get_some_loc(geo: &City) -> Option<(f64, f64)> {
-    if let Some(loc) = geo.location.as_ref() {
-        match (loc.latitude, loc.longitude) {
-            (Some(lat), Some(long)) => Some((lat, long)),
-            _ => None,
-        }
-    } else {
-        None
+    if matches.get_flag("no_forward_lookup") {
+        dns_config.allow_forward_lookup = false;
     }
-}
-
-fn get_some_org(asn: ASN) -> Option<String> {
-    match (
-        asn.autonomous_system_number,
-        asn.autonomous_system_organization,
-    ) {
-        (Some(number), Some(organization)) => Some(format!("AS{number} {organization}")),
-        (Some(number), None) => Some(format!("AS{number}")),
-        (None, Some(organization)) => Some(format!("{organization}")),
-        (None, None) => None,
+    if matches.get_flag("hide_private_range_ips") {
+        dns_config.hide_private_range_ips = true;
     }
-}
-
-fn get_some_postal(geo: &City) -> Option<String> {
-    if let Some(postal) = geo.postal.as_ref() {
-        postal.code.map(String::from)
-    } else {
-        None
+    if let Some(suffixes) = matches.get_many::<String>("hidden_suffix") {
+        dns_config.hidden_suffixes.extend(suffixes.cloned());
     }
-}
 
-fn get_some_tz(geo: &City) -> Option<String> {
-    if let Some(location) = geo.location.as_ref() {
-        location.time_zone.map(String::from)
-    } else {
-        None
-    }
+    Ok(dns_config)
 }
@@ -0,0 +1,149 @@
+use std::{net::IpAddr, net::SocketAddr, sync::Arc};
+
+use anyhow::{Context, Result};
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use axum_client_ip::{SecureClientIp, SecureClientIpSource};
+
+use crate::{
+    dns::DnsConfig,
+    geo::{self, GeoReaders},
+};
+
+/// Everything a request handler needs, built once in `run()` and shared
+/// behind an `Arc` (see `GeoReaders` for why the mmdb readers in particular
+/// stay open for the life of the process).
+struct AppState {
+    readers: GeoReaders,
+    lang_codes: Vec<String>,
+    last_subdiv: bool,
+    dns_config: DnsConfig,
+}
+
+/// Start the long-running HTTP service on `addr`, serving geo-lookups for
+/// the calling client (`GET /`) and for arbitrary addresses (`GET /{ip}`).
+///
+/// `ip_source` selects where the "real" client IP is read from when
+/// `myipinfo` sits behind a reverse proxy, mirroring echoip-slatecave's
+/// `SecureClientIpSource` (rightmost `X-Forwarded-For`, `X-Real-IP`, or the
+/// raw socket peer).
+pub fn run(
+    addr: SocketAddr,
+    readers: GeoReaders,
+    lang_codes: Vec<String>,
+    last_subdiv: bool,
+    dns_config: DnsConfig,
+    ip_source: SecureClientIpSource,
+) -> Result<()> {
+    let state = Arc::new(AppState {
+        readers,
+        lang_codes,
+        last_subdiv,
+        dns_config,
+    });
+
+    let app = Router::new()
+        .route("/", get(lookup_caller))
+        .route("/{ip}", get(lookup_addr))
+        .route("/country", get(caller_country))
+        .route("/org", get(caller_org))
+        .with_state(state)
+        .layer(ip_source.into_extension());
+
+    tokio::runtime::Runtime::new()
+        .context("Failed to start tokio runtime")?
+        .block_on(async {
+            let listener = tokio::net::TcpListener::bind(addr)
+                .await
+                .with_context(|| format!("Failed to bind {addr}"))?;
+            axum::serve(
+                listener,
+                app.into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .await
+            .context("HTTP server failed")
+        })
+}
+
+async fn lookup_caller(
+    State(state): State<Arc<AppState>>,
+    SecureClientIp(ip): SecureClientIp,
+) -> Response {
+    respond(state, ip).await
+}
+
+async fn lookup_addr(State(state): State<Arc<AppState>>, Path(ip): Path<IpAddr>) -> Response {
+    respond(state, ip).await
+}
+
+/// Runs the lookup (including its synchronous reverse-DNS syscall) on a
+/// blocking-pool thread rather than inline in the async handler, so a slow
+/// or hanging PTR lookup can't stall a tokio worker thread and, with it,
+/// every other in-flight request.
+async fn respond(state: Arc<AppState>, ip: IpAddr) -> Response {
+    let result = tokio::task::spawn_blocking(move || {
+        geo::lookup_ip_info(
+            &state.readers,
+            ip,
+            None,
+            &state.lang_codes,
+            state.last_subdiv,
+            &state.dns_config,
+        )
+    })
+    .await;
+
+    match result {
+        Ok(Ok(Ok(info))) => Json(info).into_response(),
+        Ok(Ok(Err(bogon))) => Json(bogon).into_response(),
+        Ok(Err(err)) => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response(),
+        Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response(),
+    }
+}
+
+// Plain-text single-field endpoints for shell scripting, e.g.
+// `curl https://example.com/country`.
+
+async fn caller_country(
+    State(state): State<Arc<AppState>>,
+    SecureClientIp(ip): SecureClientIp,
+) -> Response {
+    respond_field(state, ip, |info| info.country_iso).await
+}
+
+async fn caller_org(
+    State(state): State<Arc<AppState>>,
+    SecureClientIp(ip): SecureClientIp,
+) -> Response {
+    respond_field(state, ip, |info| info.org).await
+}
+
+async fn respond_field(
+    state: Arc<AppState>,
+    ip: IpAddr,
+    field: impl FnOnce(geo::IpInfo) -> Option<String> + Send + 'static,
+) -> Response {
+    let result = tokio::task::spawn_blocking(move || {
+        geo::lookup_ip_info(
+            &state.readers,
+            ip,
+            None,
+            &state.lang_codes,
+            state.last_subdiv,
+            &state.dns_config,
+        )
+    })
+    .await;
+
+    match result {
+        Ok(Ok(Ok(info))) => field(info).unwrap_or_default().into_response(),
+        Ok(Ok(Err(_))) => "".into_response(),
+        Ok(Err(err)) => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response(),
+        Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response(),
+    }
+}